@@ -3,13 +3,16 @@
 //! Borg uses a small subset of the Python config file parser.  The specific language used here
 //! seems to be under-specified, so we'll implement enough to parse what we find in the files now.
 
-// TODO: Need to handle blank lines it likes to insert.
-
 use data_encoding::base64;
 use nom::{alpha, digit, hex_digit, line_ending, tab};
 
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::result::Result as StdResult;
 use std::str::{self, FromStr};
 
+use {ErrorKind, Result};
+
 #[derive(Debug)]
 pub enum Value {
     Int(u64),
@@ -18,8 +21,114 @@ pub enum Value {
     Base64(Vec<u8>),
 }
 
-fn make_hex(bytes: &[u8]) -> Value {
-    Value::Hex(String::from_utf8(bytes.to_owned()).unwrap())
+/// A parsed config file, organized by `[section]`, with the key/value pairs of each section kept
+/// in the order they appeared in the file.
+#[derive(Debug, Default)]
+pub struct Config {
+    sections: BTreeMap<String, Vec<(String, Value)>>,
+}
+
+impl Config {
+    /// Look up a single key within a section.
+    pub fn get(&self, section: &str, key: &str) -> Option<&Value> {
+        self.sections.get(section)?
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v)
+    }
+
+    /// The key/value pairs of a single section, in file order.
+    pub fn section(&self, section: &str) -> Option<&[(String, Value)]> {
+        self.sections.get(section).map(|entries| entries.as_slice())
+    }
+}
+
+/// Parse a whole config file into a `Config`, keyed by section.
+pub fn parse(input: &[u8]) -> Result<Config> {
+    let list = match entries(input) {
+        ::nom::IResult::Done(rest, list) => {
+            if !rest.is_empty() {
+                return Err(ErrorKind::ConfigError(
+                    format!("{} bytes of trailing garbage in config file", rest.len())).into());
+            }
+            list
+        }
+        _ => return Err(ErrorKind::ConfigError("Unable to parse config file".to_string()).into()),
+    };
+
+    let mut sections: BTreeMap<String, Vec<(String, Value)>> = BTreeMap::new();
+    let mut current = String::new();
+    sections.entry(current.clone()).or_default();
+
+    for (key, value) in list {
+        if key.is_empty() {
+            if let Value::Text(name) = value {
+                current = name;
+                sections.entry(current.clone()).or_default();
+            }
+        } else {
+            sections.entry(current.clone()).or_default().push((key, value));
+        }
+    }
+
+    Ok(Config { sections: sections })
+}
+
+/// Write the config file text form of `config` to `w`, the inverse of `parse`/`entries`: a
+/// `[section]` header per section, followed by its `key = value` lines.  Entries that appeared
+/// before any `[section]` header are not written.
+pub fn write<W: Write>(config: &Config, mut w: W) -> Result<()> {
+    for (section, items) in &config.sections {
+        if section.is_empty() {
+            continue;
+        }
+
+        writeln!(w, "[{}]", section)?;
+        for (key, value) in items {
+            writeln!(w, "{} = {}", key, format_value(value))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Render a single `Value` the way the Python `ConfigParser` subset Borg uses expects it:
+/// integers and hex digits written inline, base64 re-wrapped with the newline+tab continuation
+/// that `base64`/`b64line` consume when parsing.
+fn format_value(value: &Value) -> String {
+    match *value {
+        Value::Int(n) => n.to_string(),
+        Value::Hex(ref s) => s.clone(),
+        Value::Text(ref s) => s.clone(),
+        Value::Base64(ref bytes) => wrap_base64(&base64::encode(bytes)),
+    }
+}
+
+/// Line width Borg wraps base64 config values at.
+const BASE64_WIDTH: usize = 64;
+
+fn wrap_base64(encoded: &str) -> String {
+    let mut out = String::new();
+    for (i, chunk) in encoded.as_bytes().chunks(BASE64_WIDTH).enumerate() {
+        if i > 0 {
+            out.push('\n');
+            out.push('\t');
+        }
+        out.push_str(str::from_utf8(chunk).expect("base64 output is always ASCII"));
+    }
+    out
+}
+
+fn make_hex(bytes: &[u8]) -> StdResult<Value, String> {
+    str::from_utf8(bytes)
+        .map(|s| Value::Hex(s.to_owned()))
+        .map_err(|e| format!("invalid hex value: {}", e))
+}
+
+fn make_text(bytes: &[u8]) -> StdResult<Value, String> {
+    str::from_utf8(bytes)
+        .map(|s| Value::Text(s.to_owned()))
+        .map_err(|e| format!("invalid text value: {}", e))
 }
 
 fn idchar(chr: u8) -> bool {
@@ -33,12 +142,18 @@ fn is_base64(chr: u8) -> bool {
     chr == b'+' || chr == b'/' || chr == b'='
 }
 
-fn from_base64(lines: Vec<&[u8]>) -> Value {
+fn is_text_char(chr: u8) -> bool {
+    chr != b'\n' && chr != b'\r'
+}
+
+fn from_base64(lines: Vec<&[u8]>) -> StdResult<Value, String> {
     let mut buf = vec![];
     for line in lines {
         buf.extend_from_slice(line);
     }
-    Value::Base64(base64::decode(&buf).unwrap())
+    base64::decode(&buf)
+        .map(Value::Base64)
+        .map_err(|e| format!("invalid base64 value: {:?}", e))
 }
 
 // The config files used here are more restricted.
@@ -52,10 +167,18 @@ named!(integer<u64>,
                str::from_utf8),
             FromStr::from_str));
 
+// Each of the narrower-charset alternatives below only wins if it consumes the *whole* value
+// (i.e. runs right up to the line ending): `digit` and `hex_digit` are both happy to match just a
+// prefix of a longer token (a hex id that starts with digits, or a base64 blob that starts with
+// hex letters), and without the `peek!(line_ending)` guard `alt!` would commit to that short match
+// instead of backtracking into the alternative that actually covers the whole thing.  Anything
+// left over falls through to the final catch-all, which covers plain text values like a
+// `previous_location` path.
 named!(value<Value>, alt!(
-        map!(integer, Value::Int) |
-        map!(hex_digit, make_hex) |
-        map!(base64, from_base64)));
+        map!(terminated!(integer, peek!(line_ending)), Value::Int) |
+        map_res!(terminated!(hex_digit, peek!(line_ending)), make_hex) |
+        map_res!(terminated!(base64, peek!(line_ending)), from_base64) |
+        map_res!(take_while1!(is_text_char), make_text)));
 
 // Base-64 values, spanning multiple lines.  The ConfigParser is pretty flexible, but we'll, for
 // now, just handle the newline/tab delimiter.
@@ -76,5 +199,71 @@ named!(entry<(String, Value)>,
        tag!(" = "),
        value));
 
+// A `#`/`;`-prefixed comment, up to (but not including) the line ending.
+named!(comment, recognize!(pair!(alt!(tag!("#") | tag!(";")), take_while!(|c| c != b'\n' && c != b'\r'))));
+
+// A blank line, or a comment line, including its trailing line ending.  Borg is happy to sprinkle
+// these between entries, so the lexer needs to skip over them.
+named!(junk_line, recognize!(pair!(opt!(comment), line_ending)));
+
+named!(junk, recognize!(many0!(junk_line)));
+
 named!(pub entries<Vec<(String, Value)> >,
-    terminated!(many0!(terminated!(alt!(entry | section), many1!(line_ending))), eof!()));
+    delimited!(
+        junk,
+        many0!(terminated!(alt!(entry | section), pair!(many1!(line_ending), junk))),
+        eof!()));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Parse, write back out, and re-parse, to make sure nothing is lost in a round trip.
+    fn roundtrip(text: &str) -> Config {
+        let config = parse(text.as_bytes()).expect("parse");
+        let mut out = Vec::new();
+        write(&config, &mut out).expect("write");
+        parse(&out).expect("parse of written config")
+    }
+
+    #[test]
+    fn roundtrip_int_and_hex() {
+        let text = "[repository]\nversion = 1\nid = 0123456789abcdef0123456789abcdef\n";
+        let again = roundtrip(text);
+
+        match again.get("repository", "version") {
+            Some(&Value::Int(1)) => (),
+            other => panic!("unexpected version: {:?}", other),
+        }
+        match again.get("repository", "id") {
+            Some(&Value::Hex(ref s)) => assert_eq!(s, "0123456789abcdef0123456789abcdef"),
+            other => panic!("unexpected id: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn roundtrip_text_and_base64() {
+        let text = "[repository]\nprevious_location = ssh://example/repo\nkey = aGVsbG8=\n";
+        let again = roundtrip(text);
+
+        match again.get("repository", "previous_location") {
+            Some(&Value::Text(ref s)) => assert_eq!(s, "ssh://example/repo"),
+            other => panic!("unexpected previous_location: {:?}", other),
+        }
+        match again.get("repository", "key") {
+            Some(&Value::Base64(ref b)) => assert_eq!(b, b"hello"),
+            other => panic!("unexpected key: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn tolerates_blank_and_comment_lines() {
+        let text = "# comment\n\n[repository]\n\n; another comment\nversion = 2\n\n";
+        let config = parse(text.as_bytes()).expect("parse");
+
+        match config.get("repository", "version") {
+            Some(&Value::Int(2)) => (),
+            other => panic!("unexpected version: {:?}", other),
+        }
+    }
+}
@@ -38,5 +38,10 @@ error_chain! {
             description("Unable to acquire lock")
             display("Unable to get lock at {:?}", path)
         }
+
+        ConfigError(msg: String) {
+            description("Unable to parse config file")
+            display("Unable to parse config file: {}", msg)
+        }
     }
 }
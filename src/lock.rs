@@ -7,10 +7,13 @@
 use hostname;
 use libc;
 use serde_json;
+use std::cmp;
 use std::fs::{self, File, OpenOptions};
 use std::io;
 use std::mem;
 use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use {Error, ErrorKind, Result};
 
@@ -34,33 +37,151 @@ pub struct ExclusiveLock {
 impl ExclusiveLock {
     /// Create a new exclusive lock, returning the Ok(lock) if it could be aquired.  Otherwise, it
     /// return Err to indicate that the lock could not be created.  `path` should be the directory
-    /// name of the lock.
-    /// TODO: This should have a small timeout with retries.
+    /// name of the lock.  Equivalent to `new_timeout` with a zero timeout, so this fails
+    /// immediately (after an attempt to break a stale lock) rather than waiting.
     pub fn new(dir: PathBuf) -> Result<ExclusiveLock> {
-        let file = dir.join(get_process_id().to_filename());
+        Self::new_timeout(dir, Duration::from_secs(0))
+    }
+
+    /// Create a new exclusive lock like `new`, but if the directory is already taken, keep
+    /// retrying with a growing backoff until `timeout` elapses, instead of failing immediately.
+    ///
+    /// If the directory already exists, this will check whether every holder recorded in it
+    /// (the identifying files within the directory, and any holders of the associated roster) is
+    /// confirmed dead, and if so, will break the stale lock and retry acquisition right away.
+    /// Otherwise, the roster is re-read on every pass, so a lock released by another process
+    /// during the wait is picked up on the next attempt.
+    pub fn new_timeout(dir: PathBuf, timeout: Duration) -> Result<ExclusiveLock> {
+        Self::new_timeout_aged(dir, timeout, None)
+    }
 
-        match fs::create_dir(&dir) {
-            Ok(()) => (),
-            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
-                return Err(Error::from_kind(ErrorKind::LockError(dir)));
+    /// Like `new_timeout`, but additionally requires every recorded holder to have held the lock
+    /// for at least `max_age` before it is considered stale.  Holders with no recorded
+    /// acquisition time (those known only from the identifying files in the directory, and
+    /// those from rosters predating that field) don't block this check.  Passing
+    /// `None` disables the age requirement, matching `new_timeout`.
+    pub fn new_timeout_aged(dir: PathBuf, timeout: Duration, max_age: Option<Duration>)
+        -> Result<ExclusiveLock>
+    {
+        let max_delay = Duration::from_secs(1);
+        let deadline = Instant::now() + timeout;
+        let mut delay = Duration::from_millis(50);
+
+        loop {
+            let file = dir.join(get_process_id().to_filename());
+
+            match fs::create_dir(&dir) {
+                Ok(()) => {
+                    // Create the lock at this point, so that it will be removed if there is a
+                    // problem creating the file within it.
+                    let el = ExclusiveLock {
+                        dir: dir,
+                        file: file,
+                    };
+
+                    // Make the informative file so to help identify the lock.
+                    let _ = OpenOptions::new()
+                        .write(true)
+                        .create_new(true)
+                        .open(&el.file)?;
+
+                    return Ok(el);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    if break_if_stale(&dir, max_age)? {
+                        continue;
+                    }
+
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(Error::from_kind(ErrorKind::LockError(dir)));
+                    }
+
+                    thread::sleep(cmp::min(delay, deadline - now));
+                    delay = cmp::min(delay * 2, max_delay);
+                }
+                Err(e) => return Err(e.into()),
             }
-            Err(e) => return Err(e.into()),
         }
+    }
+}
 
-        // Create the lock at this point, so that it will be removed if there is a problem creating
-        // the file within it.
-        let el = ExclusiveLock {
-            dir: dir,
-            file: file,
-        };
+/// Check whether the lock directory at `dir` is held only by processes that are confirmed dead
+/// (and, if `max_age` is given, have held it for at least that long), and if so, break it
+/// (removing the directory and pruning the associated roster) so that acquisition can be
+/// retried.  Returns `Ok(true)` if the stale lock was broken, or if the directory has already
+/// been removed by its holder, since acquisition can then be retried as well.
+fn break_if_stale(dir: &Path, max_age: Option<Duration>) -> Result<bool> {
+    let mut holders = vec![];
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e.into()),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        if let Some(name) = entry.file_name().to_str() {
+            if let Some(id) = ProcessId::from_filename(name) {
+                holders.push(id);
+            }
+        }
+    }
 
-        // Make the informative file so to help identify the lock.
-        let _ = OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(&el.file)?;
+    let rname = roster_name_for(dir);
+    let mut roster = Roster::load(&rname)?;
+    holders.extend(roster.holders().iter().cloned());
 
-        Ok(el)
+    // If nobody is on record, anyone on record is still alive, or nobody has held it long
+    // enough yet, leave the lock alone.
+    if holders.is_empty() || holders.iter().any(is_alive) || !old_enough(&holders, max_age) {
+        return Ok(false);
+    }
+
+    roster.prune_dead();
+    roster.update(&rname)?;
+    fs::remove_dir_all(dir)?;
+
+    Ok(true)
+}
+
+/// Whether every holder with a known acquisition time has held the lock for at least `max_age`.
+/// Holders with no recorded time (identifying files, old-format rosters) are assumed to qualify,
+/// and `None` always
+/// qualifies, disabling the age check entirely.
+fn old_enough(holders: &[ProcessId], max_age: Option<Duration>) -> bool {
+    let max_age = match max_age {
+        Some(max_age) => max_age.as_secs(),
+        None => return true,
+    };
+
+    let now = now_secs();
+    holders.iter().all(|h| match h.3 {
+        Some(acquired) => now.saturating_sub(acquired) >= max_age,
+        None => true,
+    })
+}
+
+/// Given the path of a `*.exclusive` lock directory, return the path of its companion roster
+/// file.
+fn roster_name_for(dir: &Path) -> PathBuf {
+    let stem = dir.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    dir.with_file_name(format!("{}.roster", stem))
+}
+
+/// Returns true if the process identified by `id` still appears to be running.  Processes on a
+/// different host than this one cannot be probed, and are conservatively assumed to be alive.
+fn is_alive(id: &ProcessId) -> bool {
+    let host = hostname::get_hostname().expect("Getting current hostname");
+    if id.0 != host {
+        return true;
+    }
+
+    if unsafe { libc::kill(id.1, 0) } == -1 {
+        io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+    } else {
+        true
     }
 }
 
@@ -112,6 +233,58 @@ impl Roster {
         self.update(path)
     }
 
+    /// Add `id` as a holder of a shared lock, creating the `Shared` roster if it was empty.
+    /// Fails if the roster is currently held exclusively.  Writes the new roster out.  This
+    /// should only be done when the surrounding exclusive lock is already taken.
+    pub fn add_shared<P: AsRef<Path>>(&mut self, id: ProcessId, path: P) -> Result<()> {
+        match *self {
+            Roster::Empty => *self = Roster::Shared(vec![id]),
+            Roster::Shared(ref mut holders) => holders.push(id),
+            Roster::Exclusive(_) => {
+                return Err(ErrorKind::LockError(path.as_ref().to_path_buf()).into());
+            }
+        }
+
+        self.update(path)
+    }
+
+    /// Remove `id` as a holder of a shared lock.  The roster becomes `Empty` once the last
+    /// holder is removed.  Writes the new roster out.  This should only be done when the
+    /// surrounding exclusive lock is already taken.
+    pub fn remove_shared<P: AsRef<Path>>(&mut self, id: &ProcessId, path: P) -> Result<()> {
+        if let Roster::Shared(ref mut holders) = *self {
+            holders.retain(|h| h != id);
+        }
+
+        if let Roster::Shared(ref holders) = *self {
+            if holders.is_empty() {
+                *self = Roster::Empty;
+            }
+        }
+
+        self.update(path)
+    }
+
+    /// All of the holders currently recorded in this roster, whether it is shared or exclusive.
+    fn holders(&self) -> &[ProcessId] {
+        match *self {
+            Roster::Empty => &[],
+            Roster::Shared(ref h) | Roster::Exclusive(ref h) => h,
+        }
+    }
+
+    /// Drop any holders that are no longer alive, collapsing to `Empty` if none remain.
+    fn prune_dead(&mut self) {
+        match *self {
+            Roster::Empty => (),
+            Roster::Shared(ref mut h) | Roster::Exclusive(ref mut h) => h.retain(is_alive),
+        }
+
+        if self.holders().is_empty() {
+            *self = Roster::Empty;
+        }
+    }
+
     /// Update the roster file with the current state.
     pub fn update<P: AsRef<Path>>(&self, path: P) -> Result<()> {
         match *self {
@@ -148,6 +321,11 @@ pub struct Lock {
 
     /// The roster.
     roster: Roster,
+
+    /// If set, a conflicting lock whose holders are all confirmed dead is only reclaimed once it
+    /// has been held for at least this long.  Holders on other hosts are never reclaimed.  Unset
+    /// by default, in which case dead holders are reclaimed regardless of age.
+    max_age: Option<Duration>,
 }
 
 impl Lock {
@@ -160,21 +338,72 @@ impl Lock {
             exclusive: None,
             id: get_process_id(),
             roster: Roster::Empty,
+            max_age: None,
         }
     }
 
+    /// Enable age-based expiry: a dead local holder's lock may be reclaimed once it has been
+    /// held for at least `max_age`, on top of the usual liveness check.
+    pub fn set_max_age(&mut self, max_age: Duration) {
+        self.max_age = Some(max_age);
+    }
+
     /// Try to aquire an exclusive lock, returning Ok if this is possible.  Otherwise will return
-    /// Err of a LockError if not possible.
-    pub fn lock_exclusive(&mut self) -> Result<()> {
+    /// Err of a LockError if not possible.  `timeout` bounds how long to wait, with backoff, for
+    /// a conflicting lock to be released before giving up.
+    pub fn lock_exclusive(&mut self, timeout: Duration) -> Result<()> {
         if self.exclusive.is_some() {
             panic!("Use error, attempt to aquire multiple locks");
         }
 
-        let el = ExclusiveLock::new(self.exclusive_name())?;
+        let el = ExclusiveLock::new_timeout_aged(self.exclusive_name(), timeout, self.max_age)?;
 
         let rname = self.roster_name();
         self.roster = Roster::load(&rname)?;
-        self.roster.make_exclusive(self.id.clone(), &rname)?;
+        self.roster.make_exclusive(self.id.stamped(), &rname)?;
+
+        self.exclusive = Some(el);
+        Ok(())
+    }
+
+    /// Try to aquire a shared lock, returning Ok if this is possible.  Otherwise will return Err
+    /// of a LockError if not possible (for example, if the lock is already held exclusively).
+    /// This briefly takes the directory lock to register ourselves in the roster, then releases
+    /// it again, so that other readers may also register.  `timeout` bounds how long to wait,
+    /// with backoff, for that directory lock before giving up.
+    pub fn lock_shared(&mut self, timeout: Duration) -> Result<()> {
+        if self.exclusive.is_some() {
+            panic!("Use error, attempt to aquire multiple locks");
+        }
+
+        let el = ExclusiveLock::new_timeout_aged(self.exclusive_name(), timeout, self.max_age)?;
+
+        let rname = self.roster_name();
+        let mut roster = Roster::load(&rname)?;
+        roster.add_shared(self.id.stamped(), &rname)?;
+        self.roster = roster;
+
+        // Dropping the directory lock here lets other readers register themselves.
+        drop(el);
+        Ok(())
+    }
+
+    /// Upgrade a held shared lock to an exclusive lock.  This only succeeds if this process is
+    /// the only holder of the shared lock.  The directory lock taken to perform the upgrade is
+    /// kept held, becoming the exclusive lock itself.
+    pub fn upgrade(&mut self) -> Result<()> {
+        let el = ExclusiveLock::new(self.exclusive_name())?;
+
+        let rname = self.roster_name();
+        let fresh = Roster::load(&rname)?;
+
+        match fresh {
+            Roster::Shared(ref holders) if holders.len() == 1 && holders[0] == self.id => (),
+            _ => return Err(ErrorKind::LockError(rname).into()),
+        }
+
+        self.roster = Roster::Empty;
+        self.roster.make_exclusive(self.id.stamped(), &rname)?;
 
         self.exclusive = Some(el);
         Ok(())
@@ -185,11 +414,47 @@ impl Lock {
         let rost = mem::replace(&mut self.roster, Roster::Empty);
         match rost {
             Roster::Empty => return Ok(()),
-            Roster::Exclusive(_) => Roster::Empty.update(self.roster_name()),
-            Roster::Shared(_) => unimplemented!(),
+            Roster::Exclusive(_) => {
+                self.exclusive = None;
+                Roster::Empty.update(self.roster_name())
+            }
+            Roster::Shared(_) => {
+                let el = ExclusiveLock::new(self.exclusive_name())?;
+
+                let rname = self.roster_name();
+                let mut roster = Roster::load(&rname)?;
+                roster.remove_shared(&self.id, &rname)?;
+
+                drop(el);
+                Ok(())
+            }
         }
     }
 
+    /// Describe who currently holds this lock and for how long, for diagnosing a backup that
+    /// appears to be hung.  This reads the roster fresh from disk, since the whole point is to
+    /// inspect a lock this process does not itself hold.  Returns `None` if, as far as the
+    /// on-disk roster shows, nobody holds it (including if the roster can't be read at all).
+    pub fn describe(&self) -> Option<String> {
+        let roster = Roster::load(self.roster_name()).unwrap_or(Roster::Empty);
+
+        let (kind, holders) = match roster {
+            Roster::Empty => return None,
+            Roster::Shared(ref h) => ("shared", h),
+            Roster::Exclusive(ref h) => ("exclusive", h),
+        };
+
+        let now = now_secs();
+        let holders: Vec<String> = holders.iter().map(|h| {
+            match h.3 {
+                Some(acquired) => format!("{} ({}s ago)", h.to_filename(), now.saturating_sub(acquired)),
+                None => format!("{} (unknown acquisition time)", h.to_filename()),
+            }
+        }).collect();
+
+        Some(format!("{} lock held by: {}", kind, holders.join(", ")))
+    }
+
     /// Get the path name for the exclusive lock.
     fn exclusive_name(&self) -> PathBuf {
         self.path.join(format!("{}.exclusive", self.base))
@@ -213,16 +478,50 @@ fn getpid() -> i32 {
     unsafe { libc::getpid() }
 }
 
-/// An identifier for the current process.  The tuple consists of the hostname, pid, and a
-/// thread-id (which is zero currently).
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
-pub struct ProcessId(pub String, pub i32, pub i32);
+/// An identifier for the current process.  The first three fields are the hostname, pid, and a
+/// thread-id (which is zero currently); the fourth is the Unix timestamp (in seconds) at which
+/// this identifier was recorded as a lock holder.  Rosters written before this field existed
+/// simply omit it, which deserializes as `None`, so old rosters still load.
+///
+/// The acquisition time is excluded from equality: two `ProcessId`s are the same holder as long
+/// as their host/pid/tid agree, regardless of when either was stamped.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProcessId(pub String, pub i32, pub i32, #[serde(default)] pub Option<u64>);
+
+impl PartialEq for ProcessId {
+    fn eq(&self, other: &ProcessId) -> bool {
+        self.0 == other.0 && self.1 == other.1 && self.2 == other.2
+    }
+}
+
+impl Eq for ProcessId {}
 
 impl ProcessId {
     /// Generate a string representation of this `ProcessId` suitable for use as a filename.
     pub fn to_filename(&self) -> String {
         format!("{}.{}-{}", self.0, self.1, self.2)
     }
+
+    /// Parse a `ProcessId` back out of a filename produced by `to_filename`.  Returns `None` if
+    /// `name` isn't in the expected `<host>.<pid>-<tid>` form.  The parsed `ProcessId` carries no
+    /// acquisition time, since the filename doesn't record one.
+    fn from_filename(name: &str) -> Option<ProcessId> {
+        let mut halves = name.rsplitn(2, '.');
+        let pid_tid = halves.next()?;
+        let host = halves.next()?;
+
+        let mut pieces = pid_tid.splitn(2, '-');
+        let pid = pieces.next()?.parse().ok()?;
+        let tid = pieces.next()?.parse().ok()?;
+
+        Some(ProcessId(host.to_string(), pid, tid, None))
+    }
+
+    /// Return a copy of this `ProcessId` stamped with the current time, suitable for recording as
+    /// a fresh roster holder.
+    fn stamped(&self) -> ProcessId {
+        ProcessId(self.0.clone(), self.1, self.2, Some(now_secs()))
+    }
 }
 
 /// Get the ProcessId identifier for the current process, used to identify locks.  The tuple
@@ -232,5 +531,136 @@ pub fn get_process_id() -> ProcessId {
     let host = hostname::get_hostname().expect("Getting current hostname");
     let pid = getpid();
 
-    ProcessId(host, pid, 0)
+    ProcessId(host, pid, 0, None)
+}
+
+/// The current wall-clock time, as a Unix timestamp in seconds.  Falls back to 0 in the
+/// practically-impossible case that the system clock predates the epoch.
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process::Command;
+
+    /// A scratch path under the system temp directory, unique to this test, with anything left
+    /// over from a previous run cleared out first.
+    fn temp_path(name: &str) -> PathBuf {
+        let mut p = env::temp_dir();
+        p.push(format!("borp-lock-test-{}-{}", getpid(), name));
+        let _ = fs::remove_dir_all(&p);
+        p
+    }
+
+    /// The pid of a process that has already exited, for exercising the "dead holder" path of
+    /// `is_alive` without touching a real, still-running pid.
+    fn dead_pid() -> i32 {
+        let mut child = Command::new("true").spawn().expect("spawn child process");
+        let pid = child.id() as i32;
+        child.wait().expect("wait for child process");
+        pid
+    }
+
+    #[test]
+    fn break_if_stale_reclaims_dead_holder() {
+        let dir = temp_path("dead.exclusive");
+        fs::create_dir(&dir).unwrap();
+
+        let host = hostname::get_hostname().unwrap();
+        let dead = ProcessId(host, dead_pid(), 0, None);
+        File::create(dir.join(dead.to_filename())).unwrap();
+
+        assert!(break_if_stale(&dir, None).unwrap());
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn break_if_stale_leaves_live_holder() {
+        let dir = temp_path("live.exclusive");
+        fs::create_dir(&dir).unwrap();
+
+        let me = get_process_id();
+        File::create(dir.join(me.to_filename())).unwrap();
+
+        assert!(!break_if_stale(&dir, None).unwrap());
+        assert!(dir.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn new_timeout_picks_up_release_mid_wait() {
+        let dir = temp_path("retry.exclusive");
+
+        let first = ExclusiveLock::new(dir.clone()).unwrap();
+
+        let waiting_dir = dir.clone();
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(150));
+            drop(first);
+        });
+
+        let second = ExclusiveLock::new_timeout(waiting_dir, Duration::from_secs(2)).unwrap();
+        releaser.join().unwrap();
+        drop(second);
+    }
+
+    #[test]
+    fn new_timeout_honors_deadline() {
+        let dir = temp_path("deadline.exclusive");
+        let _held = ExclusiveLock::new(dir.clone()).unwrap();
+
+        let start = Instant::now();
+        let result = ExclusiveLock::new_timeout(dir, Duration::from_millis(100));
+
+        assert!(result.is_err());
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn break_if_stale_respects_max_age_for_young_holder() {
+        let dir = temp_path("aged.exclusive");
+        fs::create_dir(&dir).unwrap();
+
+        let host = hostname::get_hostname().unwrap();
+        let dead = ProcessId(host, dead_pid(), 0, None);
+        File::create(dir.join(dead.to_filename())).unwrap();
+
+        let rname = roster_name_for(&dir);
+        let mut roster = Roster::Exclusive(vec![dead.stamped()]);
+        roster.update(&rname).unwrap();
+
+        assert!(!break_if_stale(&dir, Some(Duration::from_secs(60))).unwrap());
+        assert!(dir.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+        let _ = fs::remove_file(&rname);
+    }
+
+    #[test]
+    fn describe_formats_known_and_unknown_acquisition_time() {
+        let path = temp_path("describe-dir");
+        fs::create_dir_all(&path).unwrap();
+
+        let lock = Lock::new(path.clone(), "lock".to_string());
+
+        let known = ProcessId("host-a".to_string(), 111, 0, Some(now_secs().saturating_sub(5)));
+        let unknown = ProcessId("host-b".to_string(), 222, 0, None);
+        let mut roster = Roster::Shared(vec![known, unknown]);
+        roster.update(lock.roster_name()).unwrap();
+
+        let desc = lock.describe().expect("lock should be described");
+        assert!(desc.starts_with("shared lock held by: "));
+        assert!(desc.contains("host-a.111-0 ("));
+        assert!(desc.contains("s ago)"));
+        assert!(desc.contains("host-b.222-0 (unknown acquisition time)"));
+
+        fs::remove_dir_all(&path).unwrap();
+    }
 }
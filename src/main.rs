@@ -21,10 +21,11 @@ use borp::lock::Lock;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
+use std::time::Duration;
 
 fn main() {
     let mut lock = Lock::new(Path::new(".").to_path_buf(), "lock".to_string());
-    lock.lock_shared().unwrap();
+    lock.lock_shared(Duration::from_secs(5)).unwrap();
     println!("lock: {:?}", lock);
     {
         use std::process::Command;
@@ -34,7 +35,7 @@ fn main() {
 
     let mut data: Vec<u8> = vec![];
     File::open("config").unwrap().read_to_end(&mut data).unwrap();
-    println!("parse: {:?}", borp::config::entries(&data));
-    // let conf: toml::Value = toml::from_slice(&data).unwrap();
-    // println!("config: {:?}", conf);
+    let conf = borp::config::parse(&data).unwrap();
+    println!("repository id: {:?}", conf.get("repository", "id"));
+    println!("config: {:?}", conf);
 }